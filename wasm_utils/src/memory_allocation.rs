@@ -1,4 +1,6 @@
 use error::RibosomeReturnCode;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 
 //--------------------------------------------------------------------------------------------------
 // Helpers
@@ -13,7 +15,7 @@ pub fn u32_high_bits(i: u32) -> u16 {
 
 /// returns the u16 low bits from a u32
 pub fn u32_low_bits(i: u32) -> u16 {
-    (i as u16 % <u16>::max_value())
+    i as u16
 }
 
 /// splits the high and low bits of u32 into a tuple of u16, for destructuring convenience
@@ -26,6 +28,28 @@ pub fn u32_merge_bits(high: u16, low: u16) -> u32 {
     (u32::from(high) << 16) | u32::from(low)
 }
 
+const U32_MAX: u64 = <u32>::max_value() as u64;
+
+/// returns the u32 high bits from a u64
+pub fn u64_high_bits(i: u64) -> u32 {
+    (i >> 32) as u32
+}
+
+/// returns the u32 low bits from a u64
+pub fn u64_low_bits(i: u64) -> u32 {
+    i as u32
+}
+
+/// splits the high and low bits of a u64 into a tuple of u32, for destructuring convenience
+pub fn u64_split_bits(i: u64) -> (u32, u32) {
+    (u64_high_bits(i), u64_low_bits(i))
+}
+
+/// merges 2x u32 into a single u64
+pub fn u64_merge_bits(high: u32, low: u32) -> u64 {
+    (u64::from(high) << 32) | u64::from(low)
+}
+
 //--------------------------------------------------------------------------------------------------
 // Single Page Memory Allocation
 //--------------------------------------------------------------------------------------------------
@@ -37,8 +61,6 @@ pub struct SinglePageAllocation {
     pub length: u16,
 }
 
-#[allow(unknown_lints)]
-#[allow(cast_lossless)]
 impl SinglePageAllocation {
     /// An Encoded Allocation is a u32 where 'offset' is first 16-bits and 'length' last 16-bits
     /// A valid allocation must not have a length of zero
@@ -56,11 +78,10 @@ impl SinglePageAllocation {
 
         // should never happen
         // we don't panic because this needs to work with wasm, which doesn't support panic
-        if (allocation.offset as u32 + allocation.length as u32) > U16_MAX {
-            return Err(RibosomeReturnCode::OutOfMemory);
+        match u32::from(allocation.offset).checked_add(u32::from(allocation.length)) {
+            Some(total) if total <= U16_MAX => Ok(allocation),
+            _ => Err(RibosomeReturnCode::OutOfMemory),
         }
-
-        Ok(allocation)
     }
 
     /// returns a single u32 value encoding both the u16 offset and length values
@@ -69,6 +90,92 @@ impl SinglePageAllocation {
     }
 }
 
+//--------------------------------------------------------------------------------------------------
+// Wasm Memory Allocation
+//--------------------------------------------------------------------------------------------------
+
+#[derive(Copy, Clone, Debug)]
+/// WasmAllocation is a memory allocation that does not need to fit in a single WASM 64KiB page,
+/// for values (e.g. large serialized entries) that can span multiple pages of linear memory
+pub struct WasmAllocation {
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl WasmAllocation {
+    /// An Encoded Allocation is a u64 where 'offset' is the first 32-bits and 'length' the last 32-bits
+    /// A valid allocation must not have a length of zero
+    /// An Encoded Allocation with an offset but no length is actually an encoding of an ErrorCode
+    pub fn new(encoded_allocation: u64) -> Result<Self, RibosomeReturnCode> {
+        let (offset, length) = u64_split_bits(encoded_allocation);
+        let allocation = WasmAllocation { offset, length };
+
+        // zero length allocation = encoding an error api return code
+        if allocation.length == 0 {
+            // @TODO is it right to return success as Err for 0? what is a "success" error?
+            // @see https://github.com/holochain/holochain-rust/issues/181
+            // the error code scheme is only defined over u16, so an offset that doesn't fit
+            // is nonsense input rather than a known code, same as any other bad offset
+            let code = u16::try_from(allocation.offset).unwrap_or(<u16>::max_value());
+            return Err(RibosomeReturnCode::from_offset(code));
+        }
+
+        // should never happen
+        // we don't panic because this needs to work with wasm, which doesn't support panic
+        match u64::from(allocation.offset).checked_add(u64::from(allocation.length)) {
+            Some(total) if total <= U32_MAX => Ok(allocation),
+            _ => Err(RibosomeReturnCode::OutOfMemory),
+        }
+    }
+
+    /// returns a single u64 value encoding both the u32 offset and length values
+    pub fn encode(self) -> u64 {
+        u64_merge_bits(self.offset, self.length)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Allocation Errors
+//--------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Represents the ways an allocation on a SinglePageStack (or similar allocator) can fail.
+/// Unlike a panic/assert these can be encoded as a RibosomeReturnCode and handed back across
+/// the wasm boundary instead of trapping the instance.
+pub enum AllocationError {
+    /// Represents an attempt to allocate zero data
+    ZeroLength,
+    /// Represents the case where a u16 is not big enough to hold the requested allocation size
+    OutOfMemory,
+    /// An allocation was made against a stack that is not in the expected state,
+    /// e.g. the offset/length of a "previous" allocation was not already on the top of the stack
+    BadStackAlignment,
+    /// A deallocate was requested for a region that is not currently an outstanding
+    /// allocation, e.g. it was already deallocated or was never handed out by `allocate`
+    NotAllocated,
+}
+
+impl From<AllocationError> for RibosomeReturnCode {
+    fn from(allocation_error: AllocationError) -> RibosomeReturnCode {
+        match allocation_error {
+            AllocationError::ZeroLength => RibosomeReturnCode::Success,
+            AllocationError::OutOfMemory => RibosomeReturnCode::OutOfMemory,
+            AllocationError::BadStackAlignment => RibosomeReturnCode::Failure,
+            AllocationError::NotAllocated => RibosomeReturnCode::Failure,
+        }
+    }
+}
+
+impl From<RibosomeReturnCode> for AllocationError {
+    fn from(ribosome_return_code: RibosomeReturnCode) -> AllocationError {
+        match ribosome_return_code {
+            RibosomeReturnCode::Success => AllocationError::ZeroLength,
+            RibosomeReturnCode::OutOfMemory => AllocationError::OutOfMemory,
+            _ => AllocationError::BadStackAlignment,
+        }
+    }
+}
+
 //--------------------------------------------------------------------------------------------------
 // Single Page Memory Stack Manager
 //--------------------------------------------------------------------------------------------------
@@ -79,34 +186,36 @@ pub struct SinglePageStack {
     top: u16,
 }
 
-#[allow(unknown_lints)]
-#[allow(cast_lossless)]
 impl SinglePageStack {
     // A stack can be initialized by giving the last know allocation on this stack
-    pub fn new(last_allocation: SinglePageAllocation) -> Self {
-        assert!(last_allocation.offset as u32 + last_allocation.length as u32 <= U16_MAX);
-        SinglePageStack {
-            top: last_allocation.offset + last_allocation.length,
-        }
+    // we don't panic because this needs to work with wasm, which doesn't support panic
+    pub fn new(last_allocation: SinglePageAllocation) -> Result<Self, AllocationError> {
+        let top = u32::from(last_allocation.offset)
+            .checked_add(u32::from(last_allocation.length))
+            .and_then(|total| u16::try_from(total).ok())
+            .ok_or(AllocationError::OutOfMemory)?;
+        Ok(SinglePageStack { top })
     }
 
-    pub fn from_encoded(encoded_last_allocation: u32) -> Self {
-        let last_allocation = SinglePageAllocation::new(encoded_last_allocation as u32);
+    pub fn from_encoded(encoded_last_allocation: u32) -> Result<Self, AllocationError> {
         let last_allocation =
-            last_allocation.expect("received error instead of valid encoded allocation");
-        assert!(last_allocation.offset as u32 + last_allocation.length as u32 <= U16_MAX);
-        return SinglePageStack::new(last_allocation);
+            SinglePageAllocation::new(encoded_last_allocation).map_err(AllocationError::from)?;
+        SinglePageStack::new(last_allocation)
     }
 
-    pub fn allocate(&mut self, size: u16) -> u16 {
-        assert!(self.top as u32 + size as u32 <= U16_MAX);
+    pub fn allocate(&mut self, size: u16) -> Result<u16, AllocationError> {
+        let new_top = u32::from(self.top)
+            .checked_add(u32::from(size))
+            .and_then(|total| u16::try_from(total).ok())
+            .ok_or(AllocationError::OutOfMemory)?;
         let offset = self.top;
-        self.top += size;
-        offset
+        self.top = new_top;
+        Ok(offset)
     }
 
     pub fn deallocate(&mut self, allocation: SinglePageAllocation) -> Result<(), ()> {
-        if self.top == allocation.offset + allocation.length {
+        let allocation_top = u32::from(allocation.offset) + u32::from(allocation.length);
+        if u32::from(self.top) == allocation_top {
             self.top = allocation.offset;
             return Ok(());
         }
@@ -119,6 +228,125 @@ impl SinglePageStack {
     }
 }
 
+//--------------------------------------------------------------------------------------------------
+// Free-list Page Allocator
+//--------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Debug)]
+/// An allocator over a single WASM 64KiB page that, unlike SinglePageStack, supports
+/// deallocating regions out of order. A sorted free list (offset -> length) is kept and
+/// first-fit scanned on allocate; deallocate re-inserts the freed region and coalesces it
+/// with any immediately adjacent free region so the page doesn't fragment under long-running use.
+pub struct PageAllocator {
+    free_regions: BTreeMap<u16, u16>,
+    /// outstanding allocations (offset -> length). `deallocate` uses this as its source of
+    /// truth to reject a region that isn't currently a live allocation (e.g. a double-free)
+    /// instead of corrupting the free list, so unlike the original leak-tracking proposal this
+    /// map itself is always compiled in; only the richer reporting surface built on top of it
+    /// (`leak_report`/`live_bytes`) stays behind `track_allocations` for builds that don't want it
+    live_regions: BTreeMap<u16, u16>,
+}
+
+impl Default for PageAllocator {
+    fn default() -> Self {
+        let mut free_regions = BTreeMap::new();
+        free_regions.insert(0, <u16>::max_value());
+        PageAllocator {
+            free_regions,
+            live_regions: BTreeMap::new(),
+        }
+    }
+}
+
+impl PageAllocator {
+    pub fn new() -> Self {
+        PageAllocator::default()
+    }
+
+    /// first-fit scan of the free list; splits the chosen region and keeps the remainder free
+    pub fn allocate(&mut self, size: u16) -> Result<u16, AllocationError> {
+        if size == 0 {
+            return Err(AllocationError::ZeroLength);
+        }
+
+        let found = self
+            .free_regions
+            .iter()
+            .find(|&(_, &length)| length >= size)
+            .map(|(&offset, &length)| (offset, length));
+
+        match found {
+            Some((offset, length)) => {
+                self.free_regions.remove(&offset);
+                if length > size {
+                    self.free_regions.insert(offset + size, length - size);
+                }
+                self.live_regions.insert(offset, size);
+                Ok(offset)
+            }
+            None => Err(AllocationError::OutOfMemory),
+        }
+    }
+
+    /// returns a freed region to the free list, merging it with the immediately preceding
+    /// and/or following free region so freed space can be reused as a single larger region
+    pub fn deallocate(&mut self, allocation: SinglePageAllocation) -> Result<(), AllocationError> {
+        if allocation.length == 0 {
+            return Err(AllocationError::ZeroLength);
+        }
+
+        // reject a region that isn't currently outstanding (e.g. a double-free), otherwise
+        // re-inserting it into the free list could silently clobber or split an existing
+        // free region that the allocator has already handed other space out of
+        if self.live_regions.get(&allocation.offset) != Some(&allocation.length) {
+            return Err(AllocationError::NotAllocated);
+        }
+        self.live_regions.remove(&allocation.offset);
+
+        let mut offset = allocation.offset;
+        let mut length = allocation.length;
+
+        // merge with the region immediately preceding this one, if any
+        if let Some((&prev_offset, &prev_length)) = self.free_regions.range(..offset).next_back() {
+            if u32::from(prev_offset) + u32::from(prev_length) == u32::from(offset) {
+                self.free_regions.remove(&prev_offset);
+                offset = prev_offset;
+                length += prev_length;
+            }
+        }
+
+        // merge with the region immediately following this one, if any
+        if let Some(next_offset) = offset.checked_add(length) {
+            if let Some(&next_length) = self.free_regions.get(&next_offset) {
+                self.free_regions.remove(&next_offset);
+                length += next_length;
+            }
+        }
+
+        self.free_regions.insert(offset, length);
+
+        Ok(())
+    }
+
+    /// returns every allocation that has been handed out by `allocate` but not yet returned
+    /// via `deallocate`, so a host/guest test harness can assert a zome call leaked nothing
+    #[cfg(feature = "track_allocations")]
+    pub fn leak_report(&self) -> Vec<SinglePageAllocation> {
+        self.live_regions
+            .iter()
+            .map(|(&offset, &length)| SinglePageAllocation { offset, length })
+            .collect()
+    }
+
+    /// total size, in bytes, of all allocations still outstanding
+    #[cfg(feature = "track_allocations")]
+    pub fn live_bytes(&self) -> u32 {
+        self.live_regions
+            .values()
+            .fold(0u32, |total, &length| total + u32::from(length))
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
 
@@ -222,4 +450,254 @@ pub mod tests {
         );
     }
 
+    #[test]
+    /// tests construction and encoding in a new wasm allocation
+    fn new_wasm_allocation() {
+        let i = 0xAAAA_AAAA_5555_5555u64;
+        let allocation = WasmAllocation::new(i).unwrap();
+
+        assert_eq!(0xAAAA_AAAAu32, allocation.offset);
+
+        assert_eq!(0x5555_5555u32, allocation.length);
+    }
+
+    #[test]
+    /// tests that a zero-length WasmAllocation decodes as an encoded error return code
+    fn new_wasm_allocation_error() {
+        assert_eq!(
+            WasmAllocation::new(0).unwrap_err(),
+            RibosomeReturnCode::Success,
+        );
+
+        assert_eq!(
+            WasmAllocation::new(u64::from(1u32) << 32).unwrap_err(),
+            RibosomeReturnCode::Failure,
+        );
+    }
+
+    #[test]
+    /// tests that a WasmAllocation returns its encoded offset/length pair as u64
+    fn wasm_allocation_encode() {
+        let i = 0xAAAA_AAAA_5555_5555u64;
+        let allocation = WasmAllocation::new(i).unwrap();
+
+        assert_eq!(i, allocation.encode());
+    }
+
+    #[test]
+    /// tests that we can split a u64 into a tuple of high/low u32 bits
+    fn u64_split_bits() {
+        assert_eq!(
+            (0xAAAA_AAAAu32, 0x5555_5555u32),
+            super::u64_split_bits(0xAAAA_AAAA_5555_5555u64),
+        );
+    }
+
+    #[test]
+    /// tests that we can merge a u32 tuple into a u64
+    fn u64_merge_bits() {
+        assert_eq!(
+            0xAAAA_AAAA_5555_5555u64,
+            super::u64_merge_bits(0xAAAA_AAAAu32, 0x5555_5555u32),
+        );
+    }
+
+    #[test]
+    /// tests that allocating zero bytes succeeds and returns the current top unadvanced,
+    /// matching the original assert!-based behavior this was made fallible from
+    fn single_page_stack_allocate_zero_size() {
+        let mut stack = SinglePageStack::default();
+
+        assert_eq!(stack.allocate(0).unwrap(), 0);
+        assert_eq!(stack.top(), 0);
+
+        stack.allocate(10).unwrap();
+        assert_eq!(stack.allocate(0).unwrap(), 10);
+        assert_eq!(stack.top(), 10);
+    }
+
+    #[test]
+    /// tests that SinglePageStack::allocate returns Err(OutOfMemory) instead of asserting
+    /// when the requested size would push `top` past U16_MAX
+    fn single_page_stack_allocate_out_of_memory() {
+        let mut stack = SinglePageStack::default();
+
+        stack.allocate(<u16>::max_value()).unwrap();
+
+        assert_eq!(
+            stack.allocate(1).unwrap_err(),
+            AllocationError::OutOfMemory,
+        );
+    }
+
+    #[test]
+    /// tests that SinglePageStack::new returns Err(OutOfMemory) instead of asserting
+    /// when the last allocation's offset + length overflows u16
+    fn single_page_stack_new_out_of_memory() {
+        let last_allocation = SinglePageAllocation {
+            offset: <u16>::max_value(),
+            length: 1,
+        };
+
+        assert_eq!(
+            SinglePageStack::new(last_allocation).unwrap_err(),
+            AllocationError::OutOfMemory,
+        );
+    }
+
+    #[test]
+    /// tests that SinglePageStack::from_encoded returns Err(OutOfMemory) instead of
+    /// asserting/expecting when the encoded last allocation overflows u16
+    fn single_page_stack_from_encoded_out_of_memory() {
+        let encoded = super::u32_merge_bits(<u16>::max_value(), 1);
+
+        assert_eq!(
+            SinglePageStack::from_encoded(encoded).unwrap_err(),
+            AllocationError::OutOfMemory,
+        );
+    }
+
+    #[test]
+    /// tests that a PageAllocator hands out non-overlapping regions via first-fit
+    fn page_allocator_allocate() {
+        let mut allocator = PageAllocator::new();
+
+        let a = allocator.allocate(100).unwrap();
+        let b = allocator.allocate(200).unwrap();
+
+        assert_eq!(0, a);
+        assert_eq!(100, b);
+    }
+
+    #[test]
+    /// tests that allocating more than is free returns OutOfMemory instead of panicking
+    fn page_allocator_out_of_memory() {
+        let mut allocator = PageAllocator::new();
+
+        assert_eq!(allocator.allocate(<u16>::max_value()).unwrap(), 0,);
+
+        assert_eq!(
+            allocator.allocate(1).unwrap_err(),
+            AllocationError::OutOfMemory,
+        );
+    }
+
+    #[test]
+    /// tests that deallocating an interior region (not the most recent allocation) succeeds,
+    /// unlike SinglePageStack which can only free from the top of the stack
+    fn page_allocator_out_of_order_deallocate() {
+        let mut allocator = PageAllocator::new();
+
+        let a = allocator.allocate(100).unwrap();
+        let b = allocator.allocate(100).unwrap();
+        let _c = allocator.allocate(100).unwrap();
+
+        allocator
+            .deallocate(SinglePageAllocation {
+                offset: a,
+                length: 100,
+            })
+            .unwrap();
+
+        // the space freed from `a` is reusable even though `b` and `c` are still allocated
+        assert_eq!(allocator.allocate(100).unwrap(), a);
+
+        allocator
+            .deallocate(SinglePageAllocation {
+                offset: b,
+                length: 100,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// tests that deallocating a region that is not currently allocated (e.g. a double-free)
+    /// is rejected instead of corrupting the free list
+    fn page_allocator_rejects_double_deallocate() {
+        let mut allocator = PageAllocator::new();
+
+        let a = allocator.allocate(100).unwrap();
+
+        allocator
+            .deallocate(SinglePageAllocation {
+                offset: a,
+                length: 100,
+            })
+            .unwrap();
+
+        assert_eq!(
+            allocator
+                .deallocate(SinglePageAllocation {
+                    offset: a,
+                    length: 100,
+                })
+                .unwrap_err(),
+            AllocationError::NotAllocated,
+        );
+
+        // the space freed by the first deallocate must still be intact and reusable
+        assert_eq!(allocator.allocate(<u16>::max_value()).unwrap(), 0);
+    }
+
+    #[test]
+    /// tests that two adjacent freed regions are coalesced back into one
+    fn page_allocator_coalesces_adjacent_regions() {
+        let mut allocator = PageAllocator::new();
+
+        let a = allocator.allocate(100).unwrap();
+        let b = allocator.allocate(100).unwrap();
+
+        allocator
+            .deallocate(SinglePageAllocation {
+                offset: a,
+                length: 100,
+            })
+            .unwrap();
+        allocator
+            .deallocate(SinglePageAllocation {
+                offset: b,
+                length: 100,
+            })
+            .unwrap();
+
+        // the two adjacent 100-byte regions should have merged back with the remainder
+        // of the page into a single free region, so a 201-byte allocation now fits
+        assert_eq!(allocator.allocate(201).unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "track_allocations")]
+    /// tests that outstanding allocations show up in the leak report until deallocated
+    fn page_allocator_leak_report() {
+        let mut allocator = PageAllocator::new();
+
+        let a = allocator.allocate(100).unwrap();
+        let _b = allocator.allocate(200).unwrap();
+
+        assert_eq!(300, allocator.live_bytes());
+
+        allocator
+            .deallocate(SinglePageAllocation {
+                offset: a,
+                length: 100,
+            })
+            .unwrap();
+
+        assert_eq!(200, allocator.live_bytes());
+        assert_eq!(1, allocator.leak_report().len());
+    }
+
+    #[test]
+    /// property: merging a (high, low) pair and splitting it back out is the identity.
+    /// sweeps the full u16 range of `high` against a representative sample of `low` values
+    /// (the endpoints and midpoint of the u16 range) rather than the full u16 x u16 cross
+    /// product, which would be a 4-billion-iteration brute force for a unit test
+    fn u32_merge_split_bits_identity_property() {
+        for high in 0..=<u16>::max_value() {
+            for &low in &[0u16, 1, 2, 32767, 32768, 65534, 65535] {
+                let merged = super::u32_merge_bits(high, low);
+                assert_eq!((high, low), super::u32_split_bits(merged));
+            }
+        }
+    }
 }